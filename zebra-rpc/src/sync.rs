@@ -1,12 +1,14 @@
 //! Syncer task for maintaining a non-finalized state in Zebra's ReadStateService via RPCs
 
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
 
+use futures::stream::{FuturesOrdered, StreamExt};
 use tower::BoxError;
 use zebra_chain::{
     block::{self, Block, Height},
     parameters::Network,
     serialization::ZcashDeserializeInto,
+    subtree::{NoteCommitmentSubtreeData, NoteCommitmentSubtreeIndex},
 };
 use zebra_node_services::rpc_client::{self, RpcRequestClient};
 use zebra_state::{
@@ -18,47 +20,65 @@ use zebra_state::{
 use crate::methods::{get_block_template_rpcs::types::hex_data::HexData, GetBlockHash};
 
 /// Syncs non-finalized blocks in the best chain from a trusted Zebra node's RPC methods.
-struct TrustedChainSync {
+///
+/// Generic over the RPC client ([`RpcRequestClient`] in production) so tests can drive it with a
+/// mock implementation of [`SyncerRpcMethods`].
+struct TrustedChainSync<RPC = RpcRequestClient>
+where
+    RPC: SyncerRpcMethods,
+{
     /// RPC client for calling Zebra's RPC methods.
-    rpc_client: RpcRequestClient,
+    rpc_client: RPC,
     /// The read state service
     db: ZebraDb,
     /// The non-finalized state - currently only contains the best chain.
     non_finalized_state: NonFinalizedState,
+    /// The blocks currently committed to `non_finalized_state`, by height, so a reorg can roll
+    /// back to a common ancestor without re-fetching blocks we already have.
+    synced_blocks: BTreeMap<Height, Arc<Block>>,
+    /// Completed Sapling note commitment subtree roots synced from the trusted node, keyed by
+    /// subtree index. They are kept only in this syncer, and are not sent to the [`ReadStateService`].
+    sapling_subtrees: BTreeMap<u16, NoteCommitmentSubtreeData<String>>,
+    /// Completed Orchard note commitment subtree roots synced from the trusted node, keyed by
+    /// subtree index. They are kept only in this syncer, and are not sent to the [`ReadStateService`].
+    orchard_subtrees: BTreeMap<u16, NoteCommitmentSubtreeData<String>>,
     /// The chain tip sender for updating [`LatestChainTip`] and [`ChainTipChange`]
     chain_tip_sender: ChainTipSender,
     /// The non-finalized state sender, for updating the [`ReadStateService`] when the non-finalized best chain changes.
     non_finalized_state_sender: tokio::sync::watch::Sender<NonFinalizedState>,
+    /// The maximum number of blocks to fetch concurrently via `getblock`, from
+    /// [`zebra_state::Config::max_concurrent_block_requests`]. A value of `1` disables batching.
+    max_concurrent_block_requests: usize,
 }
 
-impl TrustedChainSync {
+impl<RPC> TrustedChainSync<RPC>
+where
+    RPC: SyncerRpcMethods,
+{
     fn new(
-        rpc_address: SocketAddr,
+        rpc_client: RPC,
         db: ZebraDb,
         chain_tip_sender: ChainTipSender,
         non_finalized_state_sender: tokio::sync::watch::Sender<NonFinalizedState>,
+        max_concurrent_block_requests: usize,
     ) -> Self {
-        let rpc_client = RpcRequestClient::new(rpc_address);
         let non_finalized_state = NonFinalizedState::new(&db.network());
-        let initial_tip = db
-            .tip_block()
-            .map(CheckpointVerifiedBlock::from)
-            .map(ChainTipBlock::from);
-
-        let (chain_tip_sender, latest_chain_tip, chain_tip_change) =
-            ChainTipSender::new(initial_tip, &db.network());
 
         Self {
             rpc_client,
             db,
             non_finalized_state,
+            synced_blocks: BTreeMap::new(),
+            sapling_subtrees: BTreeMap::new(),
+            orchard_subtrees: BTreeMap::new(),
             chain_tip_sender,
             non_finalized_state_sender,
+            max_concurrent_block_requests: max_concurrent_block_requests.max(1),
         }
     }
 
     /// Polls `getbestblockhash` RPC method until there are new blocks in the Zebra node's non-finalized state.
-    async fn wait_for_new_blocks(&self) -> Result<(), BoxError> {
+    async fn wait_for_new_blocks(&self) -> Result<SyncPosition, BoxError> {
         // Wait until the best block hash in Zebra is different from the tip hash in this read state
         loop {
             let Some(node_block_hash) = self.rpc_client.get_best_block_hash().await else {
@@ -81,21 +101,348 @@ impl TrustedChainSync {
             };
 
             if node_block_hash != tip_hash {
-                break;
-                // break Ok(SyncPosition::new(tip_height, tip_hash, node_block_hash));
+                break Ok(SyncPosition::new(tip_height, tip_hash, node_block_hash));
             } else {
                 tokio::time::sleep(Duration::from_millis(200)).await;
             }
         }
+    }
+
+    /// Returns how many blocks to fetch concurrently from `current_tip_height`, capped at
+    /// `self.max_concurrent_block_requests` and at how far behind `node_tip_height` we are.
+    fn batch_window_for(&self, current_tip_height: Height, node_tip_height: Option<Height>) -> usize {
+        let blocks_behind = node_tip_height
+            .map(|node_tip_height| node_tip_height.0.saturating_sub(current_tip_height.0))
+            .unwrap_or(1);
+
+        self.max_concurrent_block_requests
+            .min(blocks_behind as usize)
+            .max(1)
+    }
+
+    /// Fetches the block at `height` from the trusted node and commits it to
+    /// `self.non_finalized_state`. Returns `None` on a missing or non-chaining block, indicating
+    /// a fork the caller should resolve with [`Self::resolve_fork`].
+    async fn fetch_and_commit_block(
+        &mut self,
+        height: Height,
+        parent_hash: block::Hash,
+    ) -> Option<ContextuallyVerifiedBlock> {
+        let raw_block = self.rpc_client.get_block(height).await?;
+        let best_tip = self
+            .commit_fetched_block(height, raw_block, parent_hash)
+            .await?;
+        self.sync_new_subtrees().await;
+
+        Some(best_tip)
+    }
+
+    /// Fetches up to `window` blocks from `start_height` concurrently, and commits them to
+    /// `self.non_finalized_state` in order, stopping at the first missing or non-chaining block.
+    /// Returns the last successfully committed block, same as [`Self::fetch_and_commit_block`].
+    async fn fetch_and_commit_blocks_batched(
+        &mut self,
+        start_height: Height,
+        mut parent_hash: block::Hash,
+        window: usize,
+    ) -> Option<ContextuallyVerifiedBlock> {
+        let rpc_client = self.rpc_client.clone();
+        let mut fetches: FuturesOrdered<_> = (start_height.0..)
+            .take(window)
+            .map(|height| {
+                let rpc_client = rpc_client.clone();
+                async move { (Height(height), rpc_client.get_block(Height(height)).await) }
+            })
+            .collect();
+
+        let mut best_tip = None;
+
+        while let Some((height, raw_block)) = fetches.next().await {
+            let Some(raw_block) = raw_block else {
+                break;
+            };
+
+            match self.commit_fetched_block(height, raw_block, parent_hash).await {
+                Some(tip) => {
+                    parent_hash = tip.hash;
+                    best_tip = Some(tip);
+                }
+                None => break,
+            }
+        }
+
+        // Poll for new subtrees once per window, rather than once per block, so a batch of N
+        // blocks costs one pair of `z_getsubtreesbyindex` calls instead of N.
+        if best_tip.is_some() {
+            self.sync_new_subtrees().await;
+        }
+
+        best_tip
+    }
+
+    /// Commits an already-fetched block at `height` to `self.non_finalized_state` if it chains
+    /// onto `parent_hash`. Returns `None` on failure or a fork (see [`Self::resolve_fork`]).
+    async fn commit_fetched_block(
+        &mut self,
+        height: Height,
+        raw_block: Arc<Block>,
+        parent_hash: block::Hash,
+    ) -> Option<ContextuallyVerifiedBlock> {
+        let block = SemanticallyVerifiedBlock::from(raw_block.clone());
+
+        // If the next block's previous block hash doesn't match the expected hash, there must have
+        // been a chain re-org/fork, and the caller should resolve it before continuing.
+        if block.block.header.previous_block_hash != parent_hash {
+            return None;
+        }
+
+        let finalized_tip_hash = {
+            let db = self.db.clone();
+            tokio::task::spawn_blocking(move || db.finalized_tip_hash())
+                .await
+                .ok()?
+        };
+
+        let commit_result = if finalized_tip_hash == parent_hash {
+            self.non_finalized_state.commit_new_chain(block, &self.db)
+        } else {
+            self.non_finalized_state.commit_block(block, &self.db)
+        };
+
+        match commit_result {
+            Ok(best_tip) => {
+                self.synced_blocks.insert(height, raw_block);
+                self.finalize_and_trim_synced_blocks();
+
+                Some(best_tip)
+            }
+            Err(error) => {
+                tracing::warn!(?error, "failed to commit block to non-finalized state");
+                None
+            }
+        }
+    }
+
+    /// Fetches and records any Sapling/Orchard note commitment subtrees the trusted node has
+    /// completed since we last checked. Called once per fetch, not once per block, since each
+    /// pool's subtrees only cover a new 2^16-leaf boundary roughly once every 2^16 notes.
+    async fn sync_new_subtrees(&mut self) {
+        for pool in [SAPLING_POOL, ORCHARD_POOL] {
+            let next_index = self.next_subtree_index(pool);
+
+            for (NoteCommitmentSubtreeIndex(index), data) in
+                self.rpc_client.get_subtrees(pool, next_index).await
+            {
+                self.subtrees_for_pool_mut(pool).insert(index, data);
+            }
+        }
+    }
+
+    /// Returns the next subtree index we haven't already recorded for `pool`.
+    fn next_subtree_index(&self, pool: &'static str) -> NoteCommitmentSubtreeIndex {
+        let next_index = match self.subtrees_for_pool(pool).keys().next_back() {
+            Some(index) => index + 1,
+            None => 0,
+        };
+
+        NoteCommitmentSubtreeIndex(next_index)
+    }
+
+    fn subtrees_for_pool(&self, pool: &'static str) -> &BTreeMap<u16, NoteCommitmentSubtreeData<String>> {
+        match pool {
+            SAPLING_POOL => &self.sapling_subtrees,
+            ORCHARD_POOL => &self.orchard_subtrees,
+            _ => unreachable!("SyncerRpcMethods::get_subtrees is only called with known pools"),
+        }
+    }
+
+    fn subtrees_for_pool_mut(
+        &mut self,
+        pool: &'static str,
+    ) -> &mut BTreeMap<u16, NoteCommitmentSubtreeData<String>> {
+        match pool {
+            SAPLING_POOL => &mut self.sapling_subtrees,
+            ORCHARD_POOL => &mut self.orchard_subtrees,
+            _ => unreachable!("SyncerRpcMethods::get_subtrees is only called with known pools"),
+        }
+    }
+
+    /// Finalizes blocks past [`MAX_BLOCK_REORG_HEIGHT`], and drops the corresponding entries from
+    /// `self.synced_blocks`, since we can no longer roll back to them.
+    fn finalize_and_trim_synced_blocks(&mut self) {
+        while self
+            .non_finalized_state
+            .best_chain_len()
+            .expect("just successfully inserted a non-finalized block above")
+            > MAX_BLOCK_REORG_HEIGHT
+        {
+            tracing::trace!("finalizing block past the reorg limit");
+            self.non_finalized_state.finalize();
+        }
+
+        if let Some((root_height, _)) = self.non_finalized_state.best_tip() {
+            let first_non_finalized_height = Height(
+                root_height.0 + 1
+                    - self
+                        .non_finalized_state
+                        .best_chain_len()
+                        .expect("just successfully inserted a non-finalized block above"),
+            );
+
+            self.synced_blocks
+                .retain(|&height, _| height >= first_non_finalized_height);
+        }
+    }
+
+    /// Looks up the hash we have recorded at `height`, either among the non-finalized blocks
+    /// we've synced, or in the finalized state.
+    async fn hash_at(&self, height: Height) -> Option<block::Hash> {
+        if let Some(hash) = self.synced_blocks.get(&height).map(|block| block.hash()) {
+            return Some(hash);
+        }
+
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || db.hash(height))
+            .await
+            .ok()?
+    }
+
+    /// Walks backwards from the node's reported best hash via `getblock` header lookups to find
+    /// a common ancestor, rolls `self.non_finalized_state` back to it, and returns its height and
+    /// hash. Returns `None` if no ancestor was found within [`MAX_BLOCK_REORG_HEIGHT`] blocks.
+    async fn resolve_fork(&mut self, node_tip_hash: block::Hash) -> Option<(Height, block::Hash)> {
+        let mut header = self.rpc_client.get_block_header(node_tip_hash).await?;
+
+        for _ in 0..=MAX_BLOCK_REORG_HEIGHT {
+            if self.hash_at(header.height()).await == Some(header.hash) {
+                self.roll_back_to(header.height()).await;
+                return Some((header.height(), header.hash));
+            }
+
+            header = self
+                .rpc_client
+                .get_block_header(header.previous_block_hash?)
+                .await?;
+        }
 
-        Ok(())
+        None
+    }
+
+    /// Discards any synced blocks and subtree roots above `height`, and rebuilds
+    /// `self.non_finalized_state` from the blocks that remain.
+    async fn roll_back_to(&mut self, height: Height) {
+        self.synced_blocks.retain(|&block_height, _| block_height <= height);
+        self.sapling_subtrees
+            .retain(|_, subtree| subtree.end <= height);
+        self.orchard_subtrees
+            .retain(|_, subtree| subtree.end <= height);
+
+        let mut non_finalized_state = NonFinalizedState::new(&self.db.network());
+        let finalized_tip_hash = {
+            let db = self.db.clone();
+            let Ok(finalized_tip_hash) =
+                tokio::task::spawn_blocking(move || db.finalized_tip_hash()).await
+            else {
+                tracing::warn!(
+                    "failed to join blocking task while rolling back a fork, leaving the \
+                     non-finalized state unchanged"
+                );
+                return;
+            };
+
+            finalized_tip_hash
+        };
+
+        for block in self.synced_blocks.values() {
+            let block = SemanticallyVerifiedBlock::from(block.clone());
+            let parent_hash = block.block.header.previous_block_hash;
+
+            let commit_result = if finalized_tip_hash == parent_hash {
+                non_finalized_state.commit_new_chain(block, &self.db)
+            } else {
+                non_finalized_state.commit_block(block, &self.db)
+            };
+
+            if let Err(error) = commit_result {
+                tracing::warn!(?error, "failed to re-commit block while rolling back a fork");
+            }
+        }
+
+        self.non_finalized_state = non_finalized_state;
     }
 
     /// Starts syncing blocks from the node's non-finalized best chain.
-    async fn sync(&self) {
+    async fn sync(&mut self) {
         loop {
             // Wait until the best block hash in Zebra is different from the tip hash in this read state
-            self.wait_for_new_blocks().await;
+            let sync_position = match self.wait_for_new_blocks().await {
+                Ok(sync_position) => sync_position,
+                Err(error) => {
+                    tracing::warn!(?error, "failed to poll for new blocks, retrying");
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    continue;
+                }
+            };
+
+            let SyncPosition {
+                mut current_tip_height,
+                mut current_tip_hash,
+                node_tip_hash,
+            } = sync_position;
+
+            // Look up how far ahead the node's tip is, so we know whether it's worth fetching a
+            // batch of blocks concurrently rather than one at a time. If the lookup fails, we
+            // just fall back to fetching one block at a time below.
+            let node_tip_height = self
+                .rpc_client
+                .get_block_header(node_tip_hash)
+                .await
+                .map(|header| header.height());
+
+            loop {
+                let next_height = Height(current_tip_height.0 + 1);
+                let window = self.batch_window_for(current_tip_height, node_tip_height);
+
+                let fetched_tip = if window > 1 {
+                    self.fetch_and_commit_blocks_batched(next_height, current_tip_hash, window)
+                        .await
+                } else {
+                    self.fetch_and_commit_block(next_height, current_tip_hash)
+                        .await
+                };
+
+                let Some(best_tip) = fetched_tip else {
+                    // The next block didn't chain onto our tip: there's been a reorg. Walk back to
+                    // the fork point instead of discarding everything we've already synced.
+                    match self.resolve_fork(node_tip_hash).await {
+                        Some((fork_height, fork_hash)) => {
+                            current_tip_height = fork_height;
+                            current_tip_hash = fork_hash;
+                            continue;
+                        }
+                        None => {
+                            // The reorg goes back further than we can resolve: drop everything
+                            // and re-sync from the finalized tip, including any subtree roots we
+                            // recorded for the now-discarded chain segment.
+                            self.non_finalized_state = NonFinalizedState::new(&self.db.network());
+                            self.synced_blocks.clear();
+                            self.sapling_subtrees.clear();
+                            self.orchard_subtrees.clear();
+                            break;
+                        }
+                    }
+                };
+
+                let best_tip_hash = best_tip.hash;
+                current_tip_height = self.update_channels(best_tip);
+                current_tip_hash = best_tip_hash;
+
+                // If the block hash matches the output from the `getbestblockhash` RPC method, we can wait until
+                // the best block hash changes to get the next block.
+                if current_tip_hash == node_tip_hash {
+                    break;
+                }
+            }
         }
     }
 
@@ -113,12 +460,15 @@ impl TrustedChainSync {
 
         tip_block_height
     }
+}
 
+impl TrustedChainSync<RpcRequestClient> {
     /// Creates a new [`TrustedChainSync`] and starts syncing blocks from the node's non-finalized best chain.
     fn spawn(
         rpc_address: SocketAddr,
         db: ZebraDb,
         non_finalized_state_sender: tokio::sync::watch::Sender<NonFinalizedState>,
+        max_concurrent_block_requests: usize,
     ) -> (LatestChainTip, ChainTipChange, tokio::task::JoinHandle<()>) {
         let initial_tip = db
             .tip_block()
@@ -128,11 +478,13 @@ impl TrustedChainSync {
         let (chain_tip_sender, latest_chain_tip, chain_tip_change) =
             ChainTipSender::new(initial_tip, &db.network());
 
-        let syncer = Self::new(
-            rpc_address,
+        let rpc_client = RpcRequestClient::new(rpc_address);
+        let mut syncer = Self::new(
+            rpc_client,
             db,
             chain_tip_sender,
             non_finalized_state_sender,
+            max_concurrent_block_requests,
         );
 
         let sync_task = tokio::spawn(async move {
@@ -151,7 +503,7 @@ impl TrustedChainSync {
 ///
 /// Returns a [`ReadStateService`], [`LatestChainTip`], [`ChainTipChange`], and
 /// a [`JoinHandle`](tokio::task::JoinHandle) for the sync task.
-fn init_read_state_with_syncer(
+pub fn init_read_state_with_syncer(
     config: zebra_state::Config,
     network: &Network,
     rpc_address: SocketAddr,
@@ -163,19 +515,40 @@ fn init_read_state_with_syncer(
 ) {
     // TODO: Return an error or panic `if config.ephemeral == true`? (It'll panic anyway but it could be useful
     //       to say it's because the state is ephemeral).
+    let max_concurrent_block_requests = config.max_concurrent_block_requests;
     let (read_state, non_finalized_state_sender) = init_read_only(config, network);
     let (latest_chain_tip, chain_tip_change, sync_task) = TrustedChainSync::spawn(
         rpc_address,
         read_state.db().clone(),
         non_finalized_state_sender,
+        max_concurrent_block_requests,
     );
 
     (read_state, latest_chain_tip, chain_tip_change, sync_task)
 }
 
-trait SyncerRpcMethods {
+/// The pool name `z_getsubtreesbyindex` expects for the Sapling note commitment tree.
+const SAPLING_POOL: &str = "sapling";
+/// The pool name `z_getsubtreesbyindex` expects for the Orchard note commitment tree.
+const ORCHARD_POOL: &str = "orchard";
+/// The maximum number of subtrees to request per `z_getsubtreesbyindex` call.
+const SUBTREE_FETCH_LIMIT: u16 = 64;
+
+trait SyncerRpcMethods: Clone {
     async fn get_best_block_hash(&self) -> Option<block::Hash>;
     async fn get_block(&self, height: block::Height) -> Option<Arc<Block>>;
+    /// Looks up the height, hash, and previous block hash of the block with the given hash, via
+    /// a verbose `getblock` call. Used to walk back through the node's best chain on a reorg,
+    /// without fetching and deserializing a full block at every height.
+    async fn get_block_header(&self, hash: block::Hash) -> Option<SyncBlockHeader>;
+    /// Fetches up to [`SUBTREE_FETCH_LIMIT`] completed note commitment subtree roots for `pool`
+    /// (`"sapling"` or `"orchard"`) starting at `start_index`, via `z_getsubtreesbyindex`. An
+    /// empty result just means none have completed yet, not that anything went wrong.
+    async fn get_subtrees(
+        &self,
+        pool: &'static str,
+        start_index: NoteCommitmentSubtreeIndex,
+    ) -> Vec<(NoteCommitmentSubtreeIndex, NoteCommitmentSubtreeData<String>)>;
 }
 
 impl SyncerRpcMethods for RpcRequestClient {
@@ -189,107 +562,82 @@ impl SyncerRpcMethods for RpcRequestClient {
     async fn get_block(&self, Height(height): Height) -> Option<Arc<Block>> {
         self.json_result_from_call("getblock", format!(r#"["{}", 0]"#, height))
             .await
-            // If we fail to get a block for any reason, we assume the block is missing and the chain hasn't grown, so there must have
-            // been a chain re-org/fork, and we can clear the non-finalized state and re-fetch every block past the finalized tip.
+            // If we fail to get a block for any reason, we assume the block is missing, which the
+            // caller resolves by looking for a fork with `SyncerRpcMethods::get_block_header`.
             // TODO: Check for the MISSING_BLOCK_ERROR_CODE?
             .ok()
-            // It should always deserialize successfully, but this resets the non-finalized state if it somehow fails
+            // It should always deserialize successfully, but this is treated the same as a missing
+            // block if it somehow fails.
             // TODO: Log a warning, or, unrelated to that, panic instead if this should never happen? Could be a bad message tho, warning sounds fine
             .and_then(|HexData(raw_block)| raw_block.zcash_deserialize_into::<Block>().ok())
             .map(Arc::new)
     }
-}
-
-/// Starts syncing non-finalized blocks from Zebra via the `getbestblockhash` and `getblock` RPC methods.
-pub async fn sync_from_rpc(
-    rpc_address: SocketAddr,
-    finalized_state: ZebraDb,
-    non_finalized_state_sender: tokio::sync::watch::Sender<NonFinalizedState>,
-) -> Result<(), BoxError> {
-    let rpc_client = RpcRequestClient::new(rpc_address);
-    let network = finalized_state.network();
-    let mut non_finalized_state = NonFinalizedState::new(&network);
 
-    loop {
-        // Wait until the best block hash in Zebra is different from the tip hash in this read state
-        let SyncPosition {
-            current_tip_height,
-            current_tip_hash,
-            node_tip_hash,
-        } = wait_for_new_blocks(&rpc_client, &finalized_state, &non_finalized_state).await?;
+    async fn get_block_header(&self, hash: block::Hash) -> Option<SyncBlockHeader> {
+        self.json_result_from_call("getblock", format!(r#"["{}", 1]"#, hash))
+            .await
+            .ok()
+    }
 
-        loop {
-            // TODO:
-            // - Impl methods for `getbestblockhash` and `getblock` on RpcRequestClient
-            // - Move non-finalized state resets below this loop, also
+    async fn get_subtrees(
+        &self,
+        pool: &'static str,
+        NoteCommitmentSubtreeIndex(start_index): NoteCommitmentSubtreeIndex,
+    ) -> Vec<(NoteCommitmentSubtreeIndex, NoteCommitmentSubtreeData<String>)> {
+        let params = format!(r#"["{pool}", {start_index}, {SUBTREE_FETCH_LIMIT}]"#);
 
-            // TODO: Move all this except the `.filter()` call to a method on RpcRequestClient
-            let Some(block) = rpc_client
-                .json_result_from_call("getblock", format!(r#"["{}", 0]"#, current_tip_height.0))
-                .await
-                // If we fail to get a block for any reason, we assume the block is missing and the chain hasn't grown, so there must have
-                // been a chain re-org/fork, and we can clear the non-finalized state and re-fetch every block past the finalized tip.
-                // TODO: Check for the MISSING_BLOCK_ERROR_CODE?
-                .ok()
-                // It should always deserialize successfully, but this resets the non-finalized state if it somehow fails
-                // TODO: Log a warning, or, unrelated to that, panic instead if this should never happen? Could be a bad message tho, warning sounds fine
-                .and_then(|HexData(raw_block)| raw_block.zcash_deserialize_into::<Block>().ok())
-                .map(Arc::new)
-                .map(SemanticallyVerifiedBlock::from)
-                // If the next block's previous block hash doesn't match the expected hash, there must have
-                // been a chain re-org/fork, and we can clear the non-finalized state and re-fetch every block
-                // past the finalized tip.
-                .filter(|block| block.block.header.previous_block_hash == current_tip_hash)
-            else {
-                non_finalized_state = NonFinalizedState::new(&finalized_state.network());
-                non_finalized_state_sender.send(non_finalized_state.clone())?;
-                continue;
-            };
+        let Ok(response) = self
+            .json_result_from_call::<SubtreesRpcResponse>("z_getsubtreesbyindex", params)
+            .await
+        else {
+            return Vec::new();
+        };
 
-            let parent_hash = block.block.header.previous_block_hash;
-            if parent_hash != current_tip_hash {
-                non_finalized_state = NonFinalizedState::new(&finalized_state.network());
-                non_finalized_state_sender.send(non_finalized_state.clone())?;
-                continue;
-            } else {
-                let block_hash = block.hash;
+        response
+            .subtrees
+            .into_iter()
+            .enumerate()
+            .map(|(offset, subtree)| {
+                let index = NoteCommitmentSubtreeIndex(start_index + offset as u16);
+                let data = NoteCommitmentSubtreeData::new(Height(subtree.end_height), subtree.root);
 
-                let finalized_tip_hash = {
-                    let finalized_state = finalized_state.clone();
-                    tokio::task::spawn_blocking(move || finalized_state.finalized_tip_hash())
-                        .await?
-                };
+                (index, data)
+            })
+            .collect()
+    }
+}
 
-                let commit_result = if finalized_tip_hash == parent_hash {
-                    non_finalized_state.commit_new_chain(block, &finalized_state)
-                } else {
-                    non_finalized_state.commit_block(block, &finalized_state)
-                };
+/// The relevant part of `z_getsubtreesbyindex`'s response: the completed subtrees starting at
+/// the requested index, in order.
+#[derive(Debug, serde::Deserialize)]
+struct SubtreesRpcResponse {
+    subtrees: Vec<SubtreeRpcData>,
+}
 
-                if let Err(error) = commit_result {
-                    tracing::warn!(?error, "failed to commit block to non-finalized state");
-                    continue;
-                }
+/// A single completed subtree, as reported by `z_getsubtreesbyindex`.
+#[derive(Debug, serde::Deserialize)]
+struct SubtreeRpcData {
+    /// The root of the subtree, as a hex-encoded note commitment tree node.
+    root: String,
+    /// The height of the block containing the subtree's last leaf.
+    end_height: u32,
+}
 
-                while non_finalized_state
-                    .best_chain_len()
-                    .expect("just successfully inserted a non-finalized block above")
-                    > MAX_BLOCK_REORG_HEIGHT
-                {
-                    tracing::trace!("finalizing block past the reorg limit");
-                    non_finalized_state.finalize();
-                }
+/// The height, hash, and previous block hash of a block, as reported by a verbose `getblock` call.
+///
+/// This is a minimal subset of the fields Zebra's `getblock` RPC returns at verbosity 1, just
+/// enough to walk the trusted node's best chain backwards by hash.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+struct SyncBlockHeader {
+    height: u32,
+    hash: block::Hash,
+    #[serde(rename = "previousblockhash")]
+    previous_block_hash: Option<block::Hash>,
+}
 
-                if commit_result.is_ok() {
-                    let _ = non_finalized_state_sender.send(non_finalized_state.clone());
-                    // If the block hash matches the output from the `getbestblockhash` RPC method, we can wait until
-                    // the best block hash changes to get the next block.
-                    if block_hash == node_tip_hash {
-                        break;
-                    }
-                }
-            }
-        }
+impl SyncBlockHeader {
+    fn height(&self) -> Height {
+        Height(self.height)
     }
 }
 
@@ -313,35 +661,429 @@ impl SyncPosition {
     }
 }
 
-/// Polls `getbestblockhash` RPC method until there are new blocks in the Zebra node's non-finalized state.
-async fn wait_for_new_blocks(
-    rpc_client: &RpcRequestClient,
-    finalized_state: &ZebraDb,
-    non_finalized_state: &NonFinalizedState,
-) -> Result<SyncPosition, BoxError> {
-    // Wait until the best block hash in Zebra is different from the tip hash in this read state
-    loop {
-        let GetBlockHash(node_block_hash) = rpc_client
-            .json_result_from_call("getbestblockhash", "[]")
-            .await?;
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
 
-        let (tip_height, tip_hash) = if let Some(tip) = non_finalized_state.best_tip() {
-            tip
-        } else if let Some(tip) = {
-            let finalized_state = finalized_state.clone();
-            tokio::task::spawn_blocking(move || finalized_state.tip()).await?
-        } {
-            tip
-        } else {
-            // If there is no genesis block, wait 200ms and try again.
-            tokio::time::sleep(Duration::from_millis(200)).await;
-            continue;
-        };
+    use zebra_chain::serialization::ZcashDeserializeInto;
 
-        if node_block_hash != tip_hash {
-            break Ok(SyncPosition::new(tip_height, tip_hash, node_block_hash));
-        } else {
-            tokio::time::sleep(Duration::from_millis(200)).await;
+    use super::*;
+
+    /// A [`SyncerRpcMethods`] mock that serves blocks from an in-memory map, keyed by height, and
+    /// extra fabricated headers (used to simulate a reorg onto a chain we don't have full blocks
+    /// for), keyed by hash.
+    #[derive(Clone, Default)]
+    struct MockRpcMethods {
+        blocks_by_height: BTreeMap<u32, Arc<Block>>,
+        headers_by_hash: std::collections::HashMap<block::Hash, SyncBlockHeader>,
+        sapling_subtrees: Vec<(NoteCommitmentSubtreeIndex, NoteCommitmentSubtreeData<String>)>,
+        orchard_subtrees: Vec<(NoteCommitmentSubtreeIndex, NoteCommitmentSubtreeData<String>)>,
+    }
+
+    impl MockRpcMethods {
+        fn new(blocks: impl IntoIterator<Item = (u32, &'static [u8])>) -> Self {
+            let blocks_by_height: BTreeMap<u32, Arc<Block>> = blocks
+                .into_iter()
+                .map(|(height, bytes)| {
+                    let block: Block = bytes
+                        .zcash_deserialize_into()
+                        .expect("test vector should deserialize");
+                    (height, Arc::new(block))
+                })
+                .collect();
+
+            let headers_by_hash = blocks_by_height
+                .iter()
+                .map(|(&height, block)| {
+                    let header = SyncBlockHeader {
+                        height,
+                        hash: block.hash(),
+                        previous_block_hash: Some(block.header.previous_block_hash),
+                    };
+                    (header.hash, header)
+                })
+                .collect();
+
+            Self {
+                blocks_by_height,
+                headers_by_hash,
+                sapling_subtrees: Vec::new(),
+                orchard_subtrees: Vec::new(),
+            }
+        }
+
+        /// Adds a fabricated header that isn't backed by a full block, simulating a block on a
+        /// fork we haven't fetched yet.
+        fn with_fake_header(mut self, header: SyncBlockHeader) -> Self {
+            self.headers_by_hash.insert(header.hash, header);
+            self
+        }
+
+        /// Sets the subtrees the mock should report as completed for `pool` (`SAPLING_POOL` or
+        /// `ORCHARD_POOL`).
+        fn with_subtrees(
+            mut self,
+            pool: &'static str,
+            subtrees: Vec<(NoteCommitmentSubtreeIndex, NoteCommitmentSubtreeData<String>)>,
+        ) -> Self {
+            match pool {
+                SAPLING_POOL => self.sapling_subtrees = subtrees,
+                ORCHARD_POOL => self.orchard_subtrees = subtrees,
+                _ => unreachable!("test only uses known pools"),
+            }
+            self
+        }
+    }
+
+    impl SyncerRpcMethods for MockRpcMethods {
+        async fn get_best_block_hash(&self) -> Option<block::Hash> {
+            self.blocks_by_height
+                .values()
+                .last()
+                .map(|block| block.hash())
+        }
+
+        async fn get_block(&self, Height(height): Height) -> Option<Arc<Block>> {
+            self.blocks_by_height.get(&height).cloned()
+        }
+
+        async fn get_block_header(&self, hash: block::Hash) -> Option<SyncBlockHeader> {
+            self.headers_by_hash.get(&hash).copied()
+        }
+
+        async fn get_subtrees(
+            &self,
+            pool: &'static str,
+            NoteCommitmentSubtreeIndex(start_index): NoteCommitmentSubtreeIndex,
+        ) -> Vec<(NoteCommitmentSubtreeIndex, NoteCommitmentSubtreeData<String>)> {
+            let subtrees = match pool {
+                SAPLING_POOL => &self.sapling_subtrees,
+                ORCHARD_POOL => &self.orchard_subtrees,
+                _ => return Vec::new(),
+            };
+
+            subtrees
+                .iter()
+                .filter(|(NoteCommitmentSubtreeIndex(index), _)| *index >= start_index)
+                .cloned()
+                .collect()
+        }
+    }
+
+    fn new_syncer_with_blocks(
+        network: &Network,
+        blocks: impl IntoIterator<Item = (u32, &'static [u8])>,
+    ) -> TrustedChainSync<MockRpcMethods> {
+        new_syncer_with_blocks_and_batch_size(network, blocks, 1)
+    }
+
+    /// Like [`new_syncer_with_blocks`], but with a configurable `max_concurrent_block_requests`.
+    fn new_syncer_with_blocks_and_batch_size(
+        network: &Network,
+        blocks: impl IntoIterator<Item = (u32, &'static [u8])>,
+        max_concurrent_block_requests: usize,
+    ) -> TrustedChainSync<MockRpcMethods> {
+        let config = zebra_state::Config::ephemeral();
+        let (read_state, non_finalized_state_sender) = init_read_only(config, network);
+        let (chain_tip_sender, _latest_chain_tip, _chain_tip_change) =
+            ChainTipSender::new(None, network);
+
+        TrustedChainSync::new(
+            MockRpcMethods::new(blocks),
+            read_state.db().clone(),
+            chain_tip_sender,
+            non_finalized_state_sender,
+            max_concurrent_block_requests,
+        )
+    }
+
+    /// Fetching and committing the genesis block starts a new non-finalized chain, and
+    /// `update_channels` advances the chain tip to it.
+    #[tokio::test]
+    async fn fetch_and_commit_block_extends_the_non_finalized_tip() {
+        let network = Network::Mainnet;
+        let mut syncer = new_syncer_with_blocks(
+            &network,
+            [(0, &zebra_test::vectors::MAINNET_BLOCKS[&0][..])],
+        );
+
+        let best_tip = syncer
+            .fetch_and_commit_block(Height(0), block::GENESIS_PREVIOUS_BLOCK_HASH)
+            .await
+            .expect("mocked genesis block should commit");
+
+        let tip_height = syncer.update_channels(best_tip);
+        assert_eq!(tip_height, Height(0));
+    }
+
+    /// A missing block (for example, past the mocked node's tip) is reported as a fork, so the
+    /// caller can resolve the fork rather than committing a gap.
+    #[tokio::test]
+    async fn fetch_and_commit_block_reports_missing_blocks_as_a_fork() {
+        let network = Network::Mainnet;
+        let mut syncer = new_syncer_with_blocks(&network, []);
+
+        let result = syncer
+            .fetch_and_commit_block(Height(0), block::GENESIS_PREVIOUS_BLOCK_HASH)
+            .await;
+        assert!(result.is_none());
+    }
+
+    /// Syncs blocks 0..=`tip_height` from `MAINNET_BLOCKS` into `syncer`.
+    async fn sync_mainnet_blocks_to(syncer: &mut TrustedChainSync<MockRpcMethods>, tip_height: u32) {
+        let mut parent_hash = block::GENESIS_PREVIOUS_BLOCK_HASH;
+
+        for height in 0..=tip_height {
+            let best_tip = syncer
+                .fetch_and_commit_block(Height(height), parent_hash)
+                .await
+                .expect("mainnet test vector block should commit");
+            parent_hash = best_tip.hash;
+            syncer.update_channels(best_tip);
         }
     }
+
+    /// A single-block reorg: the node's reported tip is a fake block one height above our last
+    /// common ancestor. `resolve_fork` should walk back exactly one block and roll our
+    /// non-finalized state back to the ancestor, without discarding anything below it.
+    #[tokio::test]
+    async fn resolve_fork_walks_back_a_single_block_reorg() {
+        let network = Network::Mainnet;
+        let mut syncer = new_syncer_with_blocks(
+            &network,
+            [
+                (0, &zebra_test::vectors::MAINNET_BLOCKS[&0][..]),
+                (1, &zebra_test::vectors::MAINNET_BLOCKS[&1][..]),
+                (2, &zebra_test::vectors::MAINNET_BLOCKS[&2][..]),
+            ],
+        );
+        sync_mainnet_blocks_to(&mut syncer, 2).await;
+
+        let ancestor_hash = syncer
+            .hash_at(Height(1))
+            .await
+            .expect("block 1 should already be synced");
+
+        let fake_tip_hash = block::Hash([0xfa; 32]);
+        syncer.rpc_client = syncer.rpc_client.clone().with_fake_header(SyncBlockHeader {
+            height: 2,
+            hash: fake_tip_hash,
+            previous_block_hash: Some(ancestor_hash),
+        });
+
+        let (fork_height, fork_hash) = syncer
+            .resolve_fork(fake_tip_hash)
+            .await
+            .expect("a common ancestor one block back should be found");
+
+        assert_eq!(fork_height, Height(1));
+        assert_eq!(fork_hash, ancestor_hash);
+        assert_eq!(
+            syncer.non_finalized_state.best_tip().map(|(height, _)| height),
+            Some(Height(1))
+        );
+        assert!(!syncer.synced_blocks.contains_key(&Height(2)));
+    }
+
+    /// A multi-block reorg: the node's reported tip is two fake blocks above our last common
+    /// ancestor. `resolve_fork` should walk back through both fake headers before finding the
+    /// ancestor we share.
+    #[tokio::test]
+    async fn resolve_fork_walks_back_a_multi_block_reorg() {
+        let network = Network::Mainnet;
+        let mut syncer = new_syncer_with_blocks(
+            &network,
+            [
+                (0, &zebra_test::vectors::MAINNET_BLOCKS[&0][..]),
+                (1, &zebra_test::vectors::MAINNET_BLOCKS[&1][..]),
+                (2, &zebra_test::vectors::MAINNET_BLOCKS[&2][..]),
+            ],
+        );
+        sync_mainnet_blocks_to(&mut syncer, 2).await;
+
+        let ancestor_hash = syncer
+            .hash_at(Height(0))
+            .await
+            .expect("genesis block should already be synced");
+
+        let fake_height_1_hash = block::Hash([0xfb; 32]);
+        let fake_height_2_hash = block::Hash([0xfc; 32]);
+        syncer.rpc_client = syncer
+            .rpc_client
+            .clone()
+            .with_fake_header(SyncBlockHeader {
+                height: 1,
+                hash: fake_height_1_hash,
+                previous_block_hash: Some(ancestor_hash),
+            })
+            .with_fake_header(SyncBlockHeader {
+                height: 2,
+                hash: fake_height_2_hash,
+                previous_block_hash: Some(fake_height_1_hash),
+            });
+
+        let (fork_height, fork_hash) = syncer
+            .resolve_fork(fake_height_2_hash)
+            .await
+            .expect("a common ancestor two blocks back should be found");
+
+        assert_eq!(fork_height, Height(0));
+        assert_eq!(fork_hash, ancestor_hash);
+        assert_eq!(
+            syncer.non_finalized_state.best_tip().map(|(height, _)| height),
+            Some(Height(0))
+        );
+        assert!(!syncer.synced_blocks.contains_key(&Height(1)));
+        assert!(!syncer.synced_blocks.contains_key(&Height(2)));
+    }
+
+    /// Committing a block also picks up any newly-completed subtree roots the mocked node
+    /// reports, recording them per-pool in the syncer.
+    #[tokio::test]
+    async fn fetch_and_commit_block_syncs_new_subtrees() {
+        let network = Network::Mainnet;
+        let mut syncer = new_syncer_with_blocks(
+            &network,
+            [(0, &zebra_test::vectors::MAINNET_BLOCKS[&0][..])],
+        );
+        syncer.rpc_client = syncer.rpc_client.clone().with_subtrees(
+            SAPLING_POOL,
+            vec![(
+                NoteCommitmentSubtreeIndex(0),
+                NoteCommitmentSubtreeData::new(Height(0), "0".repeat(64)),
+            )],
+        );
+
+        syncer
+            .fetch_and_commit_block(Height(0), block::GENESIS_PREVIOUS_BLOCK_HASH)
+            .await
+            .expect("mocked genesis block should commit");
+
+        assert_eq!(syncer.sapling_subtrees.len(), 1);
+        assert!(syncer.orchard_subtrees.is_empty());
+    }
+
+    /// Rolling back a fork discards recorded subtree roots that ended above the fork point,
+    /// since they belonged to the discarded chain segment, but keeps the ones that didn't.
+    #[tokio::test]
+    async fn resolve_fork_clears_subtrees_above_the_fork_point() {
+        let network = Network::Mainnet;
+        let mut syncer = new_syncer_with_blocks(
+            &network,
+            [
+                (0, &zebra_test::vectors::MAINNET_BLOCKS[&0][..]),
+                (1, &zebra_test::vectors::MAINNET_BLOCKS[&1][..]),
+                (2, &zebra_test::vectors::MAINNET_BLOCKS[&2][..]),
+            ],
+        );
+        sync_mainnet_blocks_to(&mut syncer, 2).await;
+
+        syncer
+            .sapling_subtrees
+            .insert(0, NoteCommitmentSubtreeData::new(Height(1), "0".repeat(64)));
+        syncer
+            .sapling_subtrees
+            .insert(1, NoteCommitmentSubtreeData::new(Height(2), "1".repeat(64)));
+
+        let ancestor_hash = syncer
+            .hash_at(Height(1))
+            .await
+            .expect("block 1 should already be synced");
+
+        let fake_tip_hash = block::Hash([0xfa; 32]);
+        syncer.rpc_client = syncer.rpc_client.clone().with_fake_header(SyncBlockHeader {
+            height: 2,
+            hash: fake_tip_hash,
+            previous_block_hash: Some(ancestor_hash),
+        });
+
+        syncer
+            .resolve_fork(fake_tip_hash)
+            .await
+            .expect("a common ancestor one block back should be found");
+
+        assert!(syncer.sapling_subtrees.contains_key(&0));
+        assert!(!syncer.sapling_subtrees.contains_key(&1));
+    }
+
+    /// Fetching a window of blocks concurrently commits all of them, in height order, ending at
+    /// the same tip as fetching them one at a time would.
+    #[tokio::test]
+    async fn fetch_and_commit_blocks_batched_commits_the_whole_window() {
+        let network = Network::Mainnet;
+        let mut syncer = new_syncer_with_blocks_and_batch_size(
+            &network,
+            [
+                (0, &zebra_test::vectors::MAINNET_BLOCKS[&0][..]),
+                (1, &zebra_test::vectors::MAINNET_BLOCKS[&1][..]),
+                (2, &zebra_test::vectors::MAINNET_BLOCKS[&2][..]),
+            ],
+            8,
+        );
+
+        let best_tip = syncer
+            .fetch_and_commit_blocks_batched(Height(0), block::GENESIS_PREVIOUS_BLOCK_HASH, 3)
+            .await
+            .expect("all three mocked blocks should fetch and commit");
+
+        assert_eq!(best_tip.height, Height(2));
+        assert_eq!(
+            syncer.non_finalized_state.best_tip().map(|(height, _)| height),
+            Some(Height(2))
+        );
+        assert!(syncer.synced_blocks.contains_key(&Height(0)));
+        assert!(syncer.synced_blocks.contains_key(&Height(1)));
+        assert!(syncer.synced_blocks.contains_key(&Height(2)));
+    }
+
+    /// A window that runs past the mocked node's known blocks commits the blocks it does have,
+    /// and reports the rest as missing rather than erroring out.
+    #[tokio::test]
+    async fn fetch_and_commit_blocks_batched_stops_at_missing_blocks() {
+        let network = Network::Mainnet;
+        let mut syncer = new_syncer_with_blocks_and_batch_size(
+            &network,
+            [(0, &zebra_test::vectors::MAINNET_BLOCKS[&0][..])],
+            8,
+        );
+
+        let best_tip = syncer
+            .fetch_and_commit_blocks_batched(Height(0), block::GENESIS_PREVIOUS_BLOCK_HASH, 4)
+            .await
+            .expect("the one mocked block should fetch and commit");
+
+        assert_eq!(best_tip.height, Height(0));
+        assert_eq!(syncer.synced_blocks.len(), 1);
+    }
+
+    /// The batch window is capped at `max_concurrent_block_requests`, at how far behind the
+    /// node's tip we are, and falls back to fetching one block at a time when the node's tip
+    /// height couldn't be determined.
+    #[tokio::test]
+    async fn batch_window_for_is_capped_by_configured_limit_and_distance_from_tip() {
+        let network = Network::Mainnet;
+        let syncer = new_syncer_with_blocks_and_batch_size(&network, [], 8);
+
+        // Far behind the tip: capped at the configured limit.
+        assert_eq!(syncer.batch_window_for(Height(0), Some(Height(100))), 8);
+
+        // Close to the tip: capped at the number of blocks actually remaining.
+        assert_eq!(syncer.batch_window_for(Height(97), Some(Height(100))), 3);
+
+        // At the tip: always at least 1, even though there's nothing left to fetch.
+        assert_eq!(syncer.batch_window_for(Height(100), Some(Height(100))), 1);
+
+        // Node tip height unknown: falls back to fetching one block at a time.
+        assert_eq!(syncer.batch_window_for(Height(0), None), 1);
+    }
+
+    /// A syncer configured with `max_concurrent_block_requests: 1` never batches, regardless of
+    /// how far behind the node's tip it is.
+    #[tokio::test]
+    async fn batch_window_for_never_batches_when_configured_limit_is_one() {
+        let network = Network::Mainnet;
+        let syncer = new_syncer_with_blocks_and_batch_size(&network, [], 1);
+
+        assert_eq!(syncer.batch_window_for(Height(0), Some(Height(100))), 1);
+    }
 }
@@ -0,0 +1,58 @@
+//! Configuration for persistent and ephemeral state storage.
+
+use std::path::PathBuf;
+
+/// Configuration for the state service.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The root directory for storing cached data.
+    ///
+    /// Cached data includes any state that can be replicated from the network (e.g., the chain
+    /// state, the blocks, and the Sapling and Orchard note commitment trees).
+    ///
+    /// Ignored if `ephemeral` is `true`.
+    pub cache_dir: PathBuf,
+
+    /// Whether to use an ephemeral database.
+    ///
+    /// Ephemeral databases are stored in memory on Linux, and in a temporary directory on other
+    /// platforms, and are deleted when Zebra exits.
+    pub ephemeral: bool,
+
+    /// Whether to delete the old database directories when present.
+    pub delete_old_database: bool,
+
+    /// The height at which the state service should stop syncing, for debugging purposes.
+    pub debug_stop_at_height: Option<u32>,
+
+    /// The maximum number of blocks [`zebra_rpc::sync::init_read_state_with_syncer`](../zebra_rpc/sync/fn.init_read_state_with_syncer.html)'s
+    /// trusted-node syncer will fetch concurrently via `getblock`, whenever the node's tip is more
+    /// than one block ahead of ours. The number of concurrent requests is also capped at the
+    /// number of blocks between our tip and the node's tip.
+    ///
+    /// A value of `1` disables batching, and blocks are fetched one at a time. This is the
+    /// default, since most deployments stay close to the tip, where batching has no benefit.
+    pub max_concurrent_block_requests: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            cache_dir: PathBuf::from("cache"),
+            ephemeral: false,
+            delete_old_database: true,
+            debug_stop_at_height: None,
+            max_concurrent_block_requests: 1,
+        }
+    }
+}
+
+impl Config {
+    /// Returns a `Config` for a temporary, in-memory database.
+    pub fn ephemeral() -> Self {
+        Self {
+            ephemeral: true,
+            ..Self::default()
+        }
+    }
+}